@@ -1,13 +1,20 @@
 //! Check execution and reporting
 
-use super::Opt;
-use crate::rules::Rules;
+use super::{Opt, OutputFormat};
+use crate::rules::{record_from_line, Record, Rules};
+use crate::timeout;
 
 use anyhow::{bail, Context, Result};
-use std::fmt::Write;
+use std::fmt::Write as _;
 use std::fs::File;
-use std::process::{Command, Output, Stdio};
-use std::str;
+use std::io::{self, BufRead, BufReader, Read, Write as _};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::clap::crate_name;
 
 /// Return status according to Nagios guidelines.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -18,6 +25,8 @@ pub enum Status {
     Warning(usize),
     /// Line counts of messages matching critical and warning patterns
     Critical(usize, usize),
+    /// Line count of messages matching unknown patterns
+    Unknown(usize),
 }
 
 impl Default for Status {
@@ -26,6 +35,18 @@ impl Default for Status {
     }
 }
 
+impl Status {
+    /// Name used to key a `--map` severity override, e.g. `"warning"`.
+    pub fn severity_name(&self) -> &'static str {
+        match self {
+            Status::Ok(_) => "ok",
+            Status::Warning(_) => "warning",
+            Status::Critical(..) => "critical",
+            Status::Unknown(_) => "unknown",
+        }
+    }
+}
+
 /// Overall status and collection of messages which match rule patterns.
 #[derive(Debug, Default)]
 pub struct Outcome {
@@ -33,35 +54,134 @@ pub struct Outcome {
     pub message: String,
 }
 
-/// Log lines grouped into critcal and warning after applying rule sets
+impl Outcome {
+    /// Exit code implied by `status`, following Nagios conventions.
+    pub fn exit_code(&self) -> i32 {
+        match self.status {
+            Status::Ok(_) => 0,
+            Status::Warning(_) => 1,
+            Status::Critical(..) => 2,
+            Status::Unknown(_) => 3,
+        }
+    }
+
+    /// Prints the one-line Nagios summary followed by the detail message.
+    pub fn print(&self) {
+        let line = match &self.status {
+            Status::Ok(summary) => format!("OK - {}", summary),
+            Status::Warning(n) => format!("WARNING - {} warning line(s) found", n),
+            Status::Critical(c, w) => {
+                format!("CRITICAL - {} critical, {} warning line(s) found", c, w)
+            }
+            Status::Unknown(n) => format!("UNKNOWN - {} unknown line(s) found", n),
+        };
+        println!("{} {}", crate_name!(), line);
+        write!(io::stdout(), "{}", &self.message).ok();
+    }
+}
+
+/// Log lines grouped into unknown, critical and warning after applying rule sets
 #[derive(Debug)]
-pub struct Collection<'a> {
+struct Collection<'a> {
     rules: &'a Rules,
-    critical: Vec<&'a str>,
-    warning: Vec<&'a str>,
+    unknown: Vec<String>,
+    critical: Vec<String>,
+    warning: Vec<String>,
 }
 
 impl<'a> Collection<'a> {
     fn new(rules: &'a Rules) -> Self {
         Self {
             rules,
+            unknown: Vec::with_capacity(100),
             critical: Vec::with_capacity(100),
             warning: Vec::with_capacity(100),
         }
     }
 
-    fn push(&mut self, line: &'a str) {
+    /// Classifies a plain, human-rendered journal line.
+    fn push_line(&mut self, line: String) {
         if line.is_empty() || line.starts_with("-- Logs begin ") {
             return;
         }
-        if self.rules.crit.is_match(line) {
-            self.critical.push(line);
-        } else if self.rules.warn.is_match(line) {
-            self.warning.push(line);
+        let record = record_from_line(&line);
+        self.push(&record, line);
+    }
+
+    /// Classifies a `journalctl --output json` record, using `MESSAGE` (if
+    /// present) as the line shown in the report.
+    fn push_json(&mut self, line: &str) -> Result<()> {
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        let record: Record =
+            serde_json::from_str(line).context("Failed to parse journalctl JSON output")?;
+        let display = record
+            .get("MESSAGE")
+            .and_then(serde_json::Value::as_str)
+            .map(str::to_owned)
+            .unwrap_or_else(|| line.to_owned());
+        self.push(&record, display);
+        Ok(())
+    }
+
+    /// Classifies a record, preferring unknown over critical over warning so
+    /// a line that means "this check can't trust the data" always wins.
+    fn push(&mut self, record: &Record, display: String) {
+        if self.rules.unknown.is_match(record) {
+            self.unknown.push(display);
+        } else if self.rules.crit.is_match(record) {
+            self.critical.push(display);
+        } else if self.rules.warn.is_match(record) {
+            self.warning.push(display);
         }
     }
 }
 
+/// A running `journalctl` child process.
+///
+/// Its stdout is wrapped in a `BufReader` so callers can classify lines as
+/// they arrive instead of buffering the whole invocation in memory first.
+pub struct JournalCtl {
+    child: Child,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl JournalCtl {
+    fn spawn(cmd: &mut Command) -> io::Result<Self> {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = cmd.spawn()?;
+        timeout::set_child_pgid(child.id() as i32);
+        let stdout = BufReader::new(child.stdout.take().expect("stdout not piped"));
+        Ok(Self { child, stdout })
+    }
+
+    fn into_parts(self) -> (Child, BufReader<ChildStdout>) {
+        (self.child, self.stdout)
+    }
+
+    /// Waits for the process to exit, turning a non-zero exit code into an error.
+    fn finish(mut self) -> Result<()> {
+        let status = self
+            .child
+            .wait()
+            .with_context(|| "Failed to wait for journalctl")?;
+        timeout::clear_child_pgid();
+        if status.success() {
+            return Ok(());
+        }
+        let mut stderr = String::new();
+        if let Some(mut e) = self.child.stderr.take() {
+            e.read_to_string(&mut stderr).ok();
+        }
+        bail!(
+            "journalctl error: {} (exit {})",
+            stderr.trim(),
+            status.code().unwrap_or(-1)
+        )
+    }
+}
+
 /// Main data structure which controls check execution. Contains program options and rule sets.
 #[derive(Debug, Default)]
 pub struct Check {
@@ -72,16 +192,39 @@ pub struct Check {
 impl Check {
     /// Creates instance from program options. Loads specified rules file.
     pub fn new(opt: super::Opt) -> Result<Self> {
-        let rules = Rules::load(&opt.rules_yaml)?;
+        let rules = Rules::load(&opt.rules_yaml, Self::rules_cache_path(&opt).as_deref())?;
         Ok(Self { opt, rules })
     }
 
-    /// Runs journalctl. Optionally re-runs journalctl if state file contains garbage.
-    pub fn exec_journalctl(&self) -> Result<Output> {
+    /// Where a fetched remote ruleset is cached, derived from `--statefile`
+    /// since both are small pieces of run-to-run state for the same check.
+    fn rules_cache_path(opt: &super::Opt) -> Option<PathBuf> {
+        opt.statefile.as_ref().map(|sf| {
+            let mut name = sf.clone().into_os_string();
+            name.push(".rules-cache");
+            PathBuf::from(name)
+        })
+    }
+
+    fn journalctl_command(&self) -> Command {
         let mut cmd = Command::new(&self.opt.journalctl);
         cmd.arg("--no-pager")
             .arg(&format!("--since=-{}", self.opt.span))
             .stdin(Stdio::null());
+        if self.opt.timeout.is_some() {
+            // Run in its own process group so a timeout can kill the whole
+            // subtree instead of just this one process. Only done when a
+            // timeout is actually in effect: otherwise this would detach
+            // journalctl from the terminal's process group and a manual
+            // Ctrl-C would no longer reach it.
+            cmd.process_group(0);
+        }
+        if self.opt.follow {
+            cmd.arg("--follow");
+        }
+        if self.opt.output == OutputFormat::Json {
+            cmd.args(["--output", "json"]);
+        }
         if let Some(units) = &self.opt.unit {
             cmd.args(
                 units
@@ -89,38 +232,23 @@ impl Check {
                     .map(|u| format!("--unit={}", u))
                     .collect::<Vec<_>>(),
             );
+        }
         if self.opt.user {
             cmd.arg("--user");
         }
         if let Some(sf) = &self.opt.statefile {
             cmd.arg(&format!("--cursor-file={}", sf.display()));
         }
-        let mut out = cmd.output();
-        match (&self.opt.statefile, &out) {
-            (Some(sf), Ok(res))
-                if String::from_utf8_lossy(&res.stderr).contains("Failed to seek to cursor") =>
-            {
-                // This is probably caused by on old-style (pre-1.1.2) status file.
-                // Truncate the status file and try again.
-                out = File::create(sf).and_then(|_| cmd.output());
-            }
-            _ => (),
-        }
-        let out =
-            out.with_context(|| format!("Failed to execute {}", self.opt.journalctl.display()))?;
-        let code = out.status.code().unwrap_or(-1);
-        if code != 0 {
-            bail!(
-                "journalctl error: {} (exit {})",
-                String::from_utf8_lossy(&out.stderr).trim().to_owned(),
-                code
-            )
-        } else {
-            Ok(out)
-        }
+        cmd
     }
 
-    fn format_message(&self, title: &str, matches: &'_ [&'_ str]) -> String {
+    /// Spawns journalctl, yielding its still-running output stream.
+    pub fn exec_journalctl(&self) -> Result<JournalCtl> {
+        JournalCtl::spawn(&mut self.journalctl_command())
+            .with_context(|| format!("Failed to execute {}", self.opt.journalctl.display()))
+    }
+
+    fn format_message(&self, title: &str, matches: &[String]) -> String {
         let mut msg = String::with_capacity(4096);
         if matches.is_empty() {
             return msg;
@@ -141,28 +269,117 @@ impl Check {
         msg
     }
 
-    /// Evaluates journalctl output and returrns appropriate result
-    pub fn evaluate(&mut self, journal: Output) -> Result<Outcome> {
-        let mut collection = Collection::new(&self.rules);
-        let stdout = String::from_utf8_lossy(&journal.stdout);
-        for line in stdout.split('\n') {
-            collection.push(line)
+    fn summarize(&self, collection: Collection) -> Outcome {
+        let mut msg = Vec::with_capacity(3);
+        if !collection.unknown.is_empty() {
+            msg.push(self.format_message("UNKNOWN MATCHES", &collection.unknown))
         }
-        let mut msg = Vec::with_capacity(2);
         if !collection.critical.is_empty() {
             msg.push(self.format_message("CRITICAL MATCHES", &collection.critical))
         }
         if !collection.warning.is_empty() {
             msg.push(self.format_message("WARNING MATCHES", &collection.warning))
         }
-        Ok(Outcome {
-            status: match (collection.critical.len(), collection.warning.len()) {
-                (c, w) if c > 0 => Status::Critical(c, w),
-                (0, w) if w > 0 => Status::Warning(w),
-                (_, _) => Status::Ok("No matches".into()),
+        Outcome {
+            status: match (
+                collection.unknown.len(),
+                collection.critical.len(),
+                collection.warning.len(),
+            ) {
+                (u, _, _) if u > 0 => Status::Unknown(u),
+                (0, c, w) if c > 0 => Status::Critical(c, w),
+                (0, 0, w) if w > 0 => Status::Warning(w),
+                (_, _, _) => Status::Ok("No matches".into()),
             },
             message: msg.join("\n"),
-        })
+        }
+    }
+
+    /// Reads journalctl output line by line, classifying lines into the result buckets.
+    pub fn evaluate<R: BufRead>(&self, reader: R) -> Result<Outcome> {
+        let mut collection = Collection::new(&self.rules);
+        for line in reader.lines() {
+            let line = line.context("Failed to read journalctl output")?;
+            match self.opt.output {
+                OutputFormat::Text => collection.push_line(line),
+                OutputFormat::Json => collection.push_json(&line)?,
+            }
+        }
+        Ok(self.summarize(collection))
+    }
+
+    /// Runs journalctl once over `--span` and evaluates its output.
+    ///
+    /// Re-runs journalctl once if the state file turns out to contain a
+    /// pre-1.1.2 cursor that journalctl itself no longer understands.
+    pub fn run_oneshot(&mut self) -> Result<Outcome> {
+        let mut journal = self.exec_journalctl()?;
+        let out = self.evaluate(&mut journal.stdout)?;
+        if let Err(e) = journal.finish() {
+            let msg = e.to_string();
+            if let Some(sf) = &self.opt.statefile {
+                if msg.contains("Failed to seek to cursor") {
+                    // This is probably caused by an old-style (pre-1.1.2)
+                    // status file. Truncate the status file and try again.
+                    File::create(sf).context("Failed to reset state file")?;
+                    let mut journal = self.exec_journalctl()?;
+                    let out = self.evaluate(&mut journal.stdout)?;
+                    journal.finish()?;
+                    return Ok(out);
+                }
+            }
+            return Err(e);
+        }
+        Ok(out)
+    }
+
+    /// Runs journalctl with `--follow` and emits a Nagios result line every
+    /// `interval`, resetting the matched-line buckets after each emission
+    /// (suitable for feeding a passive/NSCA-style check).
+    pub fn run_follow(&mut self, interval: Duration) -> Result<()> {
+        let journal = self.exec_journalctl()?;
+        let (mut child, stdout) = journal.into_parts();
+        let (tx, rx) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            for line in stdout.lines() {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut collection = Collection::new(&self.rules);
+        loop {
+            let deadline = Instant::now() + interval;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                match rx.recv_timeout(remaining) {
+                    Ok(Ok(line)) => match self.opt.output {
+                        OutputFormat::Text => collection.push_line(line),
+                        OutputFormat::Json => collection.push_json(&line)?,
+                    },
+                    Ok(Err(e)) => return Err(e).context("Failed to read journalctl output"),
+                    Err(mpsc::RecvTimeoutError::Timeout) => break,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        reader.join().ok();
+                        let status = child.wait().context("Failed to wait for journalctl")?;
+                        timeout::clear_child_pgid();
+                        self.summarize(collection).print();
+                        if !status.success() {
+                            bail!(
+                                "journalctl exited unexpectedly (exit {})",
+                                status.code().unwrap_or(-1)
+                            );
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+            let window = std::mem::replace(&mut collection, Collection::new(&self.rules));
+            self.summarize(window).print();
+        }
     }
 }
 
@@ -176,14 +393,29 @@ mod test {
     #[test]
     fn push_to_collection() {
         let mut c = Collection::new(&RULES);
+        assert!(c.unknown.is_empty());
         assert!(c.critical.is_empty());
         assert!(c.warning.is_empty());
-        c.push(""); // should be ignored
-        c.push("-- Logs begin at Mon 2020-10-19 06:28:37 CEST"); // should be ignored
-        c.push("warning: 1");
-        c.push("error: 2");
-        assert_eq!(&c.warning, &["warning: 1"]);
-        assert_eq!(&c.critical, &["error: 2"]);
+        c.push_line(String::new()); // should be ignored
+        c.push_line("-- Logs begin at Mon 2020-10-19 06:28:37 CEST".to_owned()); // should be ignored
+        c.push_line("warning: 1".to_owned());
+        c.push_line("error: 2".to_owned());
+        assert_eq!(&c.warning, &["warning: 1".to_owned()]);
+        assert_eq!(&c.critical, &["error: 2".to_owned()]);
+        assert!(c.unknown.is_empty());
+    }
+
+    #[test]
+    fn unknown_patterns_take_priority_over_critical() {
+        let rules = Rules {
+            unknown: crate::rules::RuleSet::new(&["watchdog missing".to_owned()], &[], "unknown")
+                .unwrap(),
+            ..RULES.clone()
+        };
+        let mut c = Collection::new(&rules);
+        c.push_line("watchdog missing, error: 2".to_owned());
+        assert_eq!(&c.unknown, &["watchdog missing, error: 2".to_owned()]);
+        assert!(c.critical.is_empty());
     }
 
     fn check(journalctl_fixture: &str) -> Check {
@@ -199,13 +431,11 @@ mod test {
     #[test]
     fn run_journalctl() {
         let check = check("journalctl-cursor-file.sh");
-        assert_eq!(
-            check
-                .exec_journalctl()
-                .expect("exec_journalctl() failed")
-                .stdout,
-            fs::read(fixture("journal.txt")).unwrap()
-        );
+        let mut journal = check.exec_journalctl().expect("exec_journalctl() failed");
+        let mut stdout = Vec::new();
+        journal.stdout.read_to_end(&mut stdout).unwrap();
+        journal.finish().unwrap();
+        assert_eq!(stdout, fs::read(fixture("journal.txt")).unwrap());
     }
 
     #[test]
@@ -215,45 +445,30 @@ mod test {
         tf.flush().ok();
         let mut check = check("journalctl-cursor-file.sh");
         check.opt.statefile = Some(tf.path().into());
-        check.exec_journalctl().unwrap();
+        check.run_oneshot().unwrap();
         assert_eq!(std::fs::read_to_string(tf.path()).unwrap(), "new-format\n");
     }
 
     #[test]
     fn handle_journalctl_failure() {
-        let check = check("journalctl-error.sh");
+        let mut check = check("journalctl-error.sh");
         assert_eq!(
-            check.exec_journalctl().unwrap_err().to_string(),
+            check.run_oneshot().unwrap_err().to_string(),
             "journalctl error: dummy for testing (exit 1)"
         );
     }
 
-    use std::os::unix::process::ExitStatusExt;
-    use std::process::{ExitStatus, Output};
-
     #[test]
     fn evaluate_ok() {
-        let mut check = check("journalctl-ok.sh");
-        let o = check
-            .evaluate(Output {
-                status: ExitStatus::from_raw(0),
-                stdout: Vec::new(),
-                stderr: Vec::new(),
-            })
-            .unwrap();
+        let check = check("journalctl-ok.sh");
+        let o = check.evaluate(&b""[..]).unwrap();
         assert_eq!(o.status, Status::Ok("No matches".into()));
     }
 
     #[test]
     fn evaluate_warning() {
-        let mut check = check("journalctl-ok.sh");
-        let o = check
-            .evaluate(Output {
-                status: ExitStatus::from_raw(0),
-                stdout: "WARN 1\nWARN 2\n".as_bytes().into(),
-                stderr: Vec::new(),
-            })
-            .unwrap();
+        let check = check("journalctl-ok.sh");
+        let o = check.evaluate(&b"WARN 1\nWARN 2\n"[..]).unwrap();
         assert_eq!(o.status, Status::Warning(2));
         assert_eq!(
             o.message,
@@ -269,13 +484,9 @@ mod test {
 
     #[test]
     fn evaluate_critical() {
-        let mut check = check("journalctl-ok.sh");
+        let check = check("journalctl-ok.sh");
         let o = check
-            .evaluate(Output {
-                status: ExitStatus::from_raw(0),
-                stdout: "error: 1\nerror: 2\nwarning: 1\n".as_bytes().into(),
-                stderr: Vec::new(),
-            })
+            .evaluate(&b"error: 1\nerror: 2\nwarning: 1\n"[..])
             .unwrap();
         assert_eq!(o.status, Status::Critical(2, 1));
         assert_eq!(
@@ -295,16 +506,31 @@ mod test {
     }
 
     #[test]
-    fn report_limit() {
+    fn evaluate_json_scoped_field() {
         let mut check = check("journalctl-ok.sh");
-        check.opt.limit = 1;
+        check.opt.output = OutputFormat::Json;
+        check.rules = Rules {
+            crit: crate::rules::RuleSet::new(&["PRIORITY:^[0-3]$".to_owned()], &[], "crit")
+                .unwrap(),
+            warn: crate::rules::RuleSet::default(),
+            unknown: crate::rules::RuleSet::default(),
+        };
         let o = check
-            .evaluate(Output {
-                status: ExitStatus::from_raw(0),
-                stdout: "WARN 1\nWARN 2\n".as_bytes().into(),
-                stderr: Vec::new(),
-            })
+            .evaluate(
+                &br#"{"MESSAGE":"all fine","PRIORITY":"6"}
+{"MESSAGE":"disk full","PRIORITY":"2"}
+"#[..],
+            )
             .unwrap();
+        assert_eq!(o.status, Status::Critical(1, 0));
+        assert!(o.message.contains("disk full"));
+    }
+
+    #[test]
+    fn report_limit() {
+        let mut check = check("journalctl-ok.sh");
+        check.opt.limit = 1;
+        let o = check.evaluate(&b"WARN 1\nWARN 2\n"[..]).unwrap();
         assert_eq!(o.status, Status::Warning(2));
         assert_eq!(
             o.message,
@@ -1,6 +1,6 @@
-use std::io::{stdout, Write};
 use std::path::PathBuf;
 use std::process;
+use std::time::Duration;
 use structopt::clap::crate_name;
 use structopt::StructOpt;
 
@@ -8,8 +8,37 @@ mod check;
 mod rules;
 #[cfg(test)]
 mod tests;
+mod timeout;
+mod utils;
 
-use check::{Check, Status};
+use check::Check;
+
+/// Output format requested from `journalctl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-rendered log lines (`journalctl` default), matched as whole lines
+    Text,
+    /// `journalctl --output json`, matched per structured field
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Text
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            other => Err(format!("invalid output format {:?}, expected text or json", other)),
+        }
+    }
+}
 
 /// Nagios/Icinga compatible plugin to search `journalctl` output for matching lines
 #[derive(Debug, Default, StructOpt)]
@@ -46,37 +75,91 @@ pub struct Opt {
     // ignored, retained for compatibility
     #[structopt(short, long, hidden = true)]
     verbose: bool,
+    /// Aborts the check after SECONDS, killing journalctl and exiting UNKNOWN
+    #[structopt(short, long, value_name = "SECONDS")]
+    timeout: Option<u32>,
+    /// Runs continuously, following the live journal instead of exiting after one pass
+    ///
+    /// Emits a Nagios result line every `--follow-interval` seconds instead of a single
+    /// exit code, so it can feed a passive/NSCA-style check.
+    #[structopt(long)]
+    follow: bool,
+    /// Interval between result lines while `--follow` is active
+    #[structopt(long, default_value = "60", value_name = "SECONDS")]
+    follow_interval: u64,
+    /// Output format requested from journalctl
+    ///
+    /// In "json" mode, patterns may be scoped to a named field (e.g. `PRIORITY:^[0-3]$`,
+    /// `_SYSTEMD_UNIT:nginx\.service`) instead of matching the whole rendered line.
+    /// Unscoped patterns match the `MESSAGE` field, same as a plain text-mode pattern.
+    #[structopt(
+        long,
+        default_value = "text",
+        possible_values = &["text", "json"],
+        value_name = "FORMAT"
+    )]
+    output: OutputFormat,
+    /// Overrides the exit code for a severity, e.g. `--map warning=0` to downgrade
+    /// warning-tier matches to OK. Can be specified multiple times; valid severities
+    /// are "ok", "warning", "critical" and "unknown".
+    #[structopt(long, number_of_values = 1, value_name = "SEVERITY=EXITCODE", parse(try_from_str = parse_severity_map_entry))]
+    map: Vec<(String, i32)>,
     /// Match patterns from file or URL
     ///
-    /// In case of an URL, it will be downloaded automatically on each run. On download errors,
-    /// this plugin will exit with an UNKNOWN state.
+    /// In case of an URL, it is re-fetched on each run with a conditional request against the
+    /// last successfully downloaded copy (cached alongside `--statefile`, if given). A `304`
+    /// response uses that cached copy; a connection error or a timeout falls back to it with a
+    /// warning; only a missing cache combined with a failed fetch exits with an UNKNOWN state.
     #[structopt(parse(from_os_str), value_name = "RULES_YAML")]
     rules_yaml: PathBuf,
 }
 
+/// Valid severity names accepted by `--map`, matching `Status::severity_name`.
+const KNOWN_SEVERITIES: &[&str] = &["ok", "warning", "critical", "unknown"];
+
+/// Parses a single `SEVERITY=EXITCODE` argument for `--map`.
+fn parse_severity_map_entry(s: &str) -> Result<(String, i32), String> {
+    let (severity, code) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid --map entry {:?}, expected SEVERITY=EXITCODE", s))?;
+    if !KNOWN_SEVERITIES.contains(&severity) {
+        return Err(format!(
+            "invalid severity {:?} in --map {:?}, expected one of {}",
+            severity,
+            s,
+            KNOWN_SEVERITIES.join(", ")
+        ));
+    }
+    let code = code
+        .parse()
+        .map_err(|_| format!("invalid exit code {:?} in --map {:?}", code, s))?;
+    Ok((severity.to_owned(), code))
+}
+
+/// Exit code for `out`, taking any `--map` override for its severity over
+/// the hard-coded Nagios 0/1/2/3 convention.
+fn map_exit_code(out: &check::Outcome, overrides: &[(String, i32)]) -> i32 {
+    overrides
+        .iter()
+        .find(|(severity, _)| severity == out.status.severity_name())
+        .map_or_else(|| out.exit_code(), |(_, code)| *code)
+}
+
 fn run() -> Result<i32, anyhow::Error> {
-    let mut check = Check::new(Opt::from_args())?;
-    let out = check.evaluate(check.exec_journalctl()?)?;
-    let exitcode = match out.status {
-        Status::Ok(summary) => {
-            println!("{} OK - {}", crate_name!(), summary);
-            0
-        }
-        Status::Warning(n) => {
-            println!("{} WARNING - {} warning line(s) found", crate_name!(), n);
-            1
-        }
-        Status::Critical(c, w) => {
-            println!(
-                "{} CRITICAL - {} critical, {} warning line(s) found",
-                crate_name!(),
-                c,
-                w
-            );
-            2
-        }
-    };
-    write!(stdout(), "{}", &out.message).ok();
+    let opt = Opt::from_args();
+    if let Some(timeout) = opt.timeout {
+        timeout::install(timeout)?;
+    }
+    let follow = opt.follow.then(|| Duration::from_secs(opt.follow_interval));
+    let map = opt.map.clone();
+    let mut check = Check::new(opt)?;
+    if let Some(interval) = follow {
+        check.run_follow(interval)?;
+        return Ok(0);
+    }
+    let out = check.run_oneshot()?;
+    let exitcode = map_exit_code(&out, &map);
+    out.print();
     Ok(exitcode)
 }
 
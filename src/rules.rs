@@ -1,45 +1,130 @@
 //! Loads, parses and applies log matching rules
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{bail, Context, Result};
 use regex::RegexSet;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use structopt::clap::crate_name;
 
-/// Pair of regular expression sets for matching and excepting lines
+use crate::utils::Chrono;
+
+/// Name of the journal field patterns are matched against when a pattern
+/// does not explicitly scope itself to another field.
+const DEFAULT_FIELD: &str = "MESSAGE";
+
+/// Journal fields that may be used as an explicit `FIELD:` scope prefix.
+///
+/// Kept as an allow-list (rather than a generic "looks like an identifier"
+/// heuristic) so a pattern that merely starts with an uppercase word and a
+/// colon, e.g. `"ERROR: disk full"`, is never misread as scoping to a
+/// nonexistent field and silently stops matching.
+const KNOWN_FIELDS: &[&str] = &[
+    "MESSAGE",
+    "PRIORITY",
+    "SYSLOG_IDENTIFIER",
+    "SYSLOG_FACILITY",
+    "_SYSTEMD_UNIT",
+    "_SYSTEMD_USER_UNIT",
+    "_COMM",
+    "_EXE",
+    "_PID",
+    "_UID",
+    "_GID",
+    "_HOSTNAME",
+    "_TRANSPORT",
+    "UNIT",
+    "USER_UNIT",
+];
+
+/// A single structured journal entry, as parsed from `journalctl --output json`.
+///
+/// Text-mode matching builds a one-entry record (`MESSAGE` holding the
+/// rendered line) so both modes share the same matching code.
+pub type Record = HashMap<String, Value>;
+
+/// Builds a one-field record for matching a plain rendered journal line.
+pub fn record_from_line(line: &str) -> Record {
+    let mut record = Record::with_capacity(1);
+    record.insert(DEFAULT_FIELD.to_owned(), Value::String(line.to_owned()));
+    record
+}
+
+/// Splits a pattern into the field it targets and the regex to apply to it.
+///
+/// A pattern may be prefixed with `FIELD:` to scope it to a known journal
+/// field (see `KNOWN_FIELDS`), e.g. `PRIORITY:^[0-3]$` or `_SYSTEMD_UNIT:`.
+/// Everything else — including a pattern that merely starts with something
+/// that looks like a field name, e.g. `"ERROR: disk full"` — is matched
+/// against `MESSAGE` verbatim, which keeps plain regexes from existing rule
+/// files working unchanged.
+fn split_scope(pattern: &str) -> (&str, &str) {
+    if let Some((field, regex)) = pattern.split_once(':') {
+        if KNOWN_FIELDS.contains(&field) {
+            return (field, regex);
+        }
+    }
+    (DEFAULT_FIELD, pattern)
+}
+
+/// Compiled patterns for a single field, as part of a `RuleSet`.
 #[derive(Debug, Clone)]
+struct FieldPatterns {
+    field: String,
+    regexes: RegexSet,
+}
+
+/// Pair of (possibly per-field) pattern groups for matching and excepting records
+#[derive(Debug, Clone, Default)]
 pub struct RuleSet {
-    matches: RegexSet,
-    except: RegexSet,
+    matches: Vec<FieldPatterns>,
+    except: Vec<FieldPatterns>,
 }
 
 impl RuleSet {
     /// Create rule set from match patterns and exceptions
     ///
-    /// `title` is used to form error messages and should be either "critical" or "warrning".
+    /// `title` is used to form error messages and should be "unknown", "critical" or "warning".
     pub fn new(patterns: &[String], exceptions: &[String], title: &str) -> Result<Self> {
         Ok(Self {
-            matches: RegexSet::new(patterns)
-                .with_context(|| format!("Failed to load {} patterns", title))?,
-            except: RegexSet::new(exceptions)
-                .with_context(|| format!("Failed to load {} exceptions", title))?,
+            matches: Self::compile(patterns, title, "patterns")?,
+            except: Self::compile(exceptions, title, "exceptions")?,
         })
     }
 
-    /// Returns true if line matches a pattern but no exception
-    pub fn is_match(&self, line: &str) -> bool {
-        self.matches.is_match(line) && !self.except.is_match(line)
+    fn compile(patterns: &[String], title: &str, kind: &str) -> Result<Vec<FieldPatterns>> {
+        let mut by_field: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for pattern in patterns {
+            let (field, regex) = split_scope(pattern);
+            by_field.entry(field).or_default().push(regex);
+        }
+        by_field
+            .into_iter()
+            .map(|(field, regexes)| {
+                Ok(FieldPatterns {
+                    field: field.to_owned(),
+                    regexes: RegexSet::new(regexes)
+                        .with_context(|| format!("Failed to load {} {}", title, kind))?,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns true if the record matches a pattern but no exception
+    pub fn is_match(&self, record: &Record) -> bool {
+        Self::any_matches(&self.matches, record) && !Self::any_matches(&self.except, record)
     }
-}
 
-impl Default for RuleSet {
-    fn default() -> Self {
-        let empty: [&str; 0] = [];
-        Self {
-            matches: RegexSet::new(&empty).unwrap(),
-            except: RegexSet::new(&empty).unwrap(),
-        }
+    fn any_matches(groups: &[FieldPatterns], record: &Record) -> bool {
+        groups.iter().any(|group| {
+            record
+                .get(&group.field)
+                .and_then(Value::as_str)
+                .map_or(false, |value| group.regexes.is_match(value))
+        })
     }
 }
 
@@ -49,18 +134,64 @@ struct RulesFile {
     criticalexceptions: Vec<String>,
     warningpatterns: Vec<String>,
     warningexceptions: Vec<String>,
+    #[serde(default)]
+    unknownpatterns: Vec<String>,
+    #[serde(default)]
+    unknownexceptions: Vec<String>,
 }
 
-/// Pair of rule sets for critical and warning rules
+/// Triple of rule sets for unknown, critical and warning rules
 #[derive(Debug, Default, Clone)]
 pub struct Rules {
+    pub unknown: RuleSet,
     pub crit: RuleSet,
     pub warn: RuleSet,
 }
 
+/// On-disk cache of the last successfully fetched remote rules document.
+///
+/// Keyed by `url` so a cache file left over from a previous `rules_yaml`
+/// URL is ignored rather than served for the wrong ruleset. `last_modified`
+/// is always populated on save, falling back to the fetch time if the
+/// server didn't send one, so it can always be replayed as
+/// `If-Modified-Since` on the next run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedRules {
+    url: String,
+    etag: Option<String>,
+    last_modified: String,
+    body: String,
+}
+
+impl CachedRules {
+    /// Loads the cache, discarding it silently if it's missing, corrupt or
+    /// was written for a different URL.
+    fn load(path: &Path, url: &str) -> Option<Self> {
+        let cached: Self = serde_yaml::from_reader(File::open(path).ok()?).ok()?;
+        if cached.url == url {
+            Some(cached)
+        } else {
+            None
+        }
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        serde_yaml::to_writer(
+            File::create(path).with_context(|| format!("Cannot write rules cache {:?}", path))?,
+            self,
+        )
+        .context("Failed to write rules cache")
+    }
+}
+
 impl Rules {
     fn new(source: RulesFile) -> Result<Self> {
         Ok(Self {
+            unknown: RuleSet::new(
+                &source.unknownpatterns,
+                &source.unknownexceptions,
+                "unknown",
+            )?,
             crit: RuleSet::new(
                 &source.criticalpatterns,
                 &source.criticalexceptions,
@@ -79,28 +210,80 @@ impl Rules {
         Self::new(rulesfile)
     }
 
-    /// Gets rules specification as YAML file from either a local file path or the net
-    pub fn load<P: AsRef<Path>>(source: P) -> Result<Self> {
+    /// Gets rules specification as YAML file from either a local file path or the net.
+    ///
+    /// `cache_path`, if given, is where the last successfully fetched remote
+    /// copy is read from and written to, so a flaky server or a transient
+    /// network error doesn't turn into a hard failure on every other run.
+    pub fn load<P: AsRef<Path>>(source: P, cache_path: Option<&Path>) -> Result<Self> {
         let source = source.as_ref();
         let s = source.to_string_lossy();
         if s.contains("://") {
-            let res = ureq::get(&*s)
-                .timeout_connect(30_000)
-                .timeout_read(300_000)
-                .call();
-            ensure!(
-                res.ok(),
-                "Failed to retrieve remote rules from {}: {}",
-                s,
+            return Self::load_remote(&s, cache_path);
+        }
+        Self::parse(
+            File::open(&source).with_context(|| format!("Cannot open rules file {:?}", source))?,
+        )
+    }
+
+    /// Conditionally fetches the remote rules, falling back to `cache_path`
+    /// on a `304 Not Modified`, a connection error or a timeout.
+    ///
+    /// The cache is only refreshed on an actual `200`. If there is no usable
+    /// cache and the fetch fails, this still returns an error, same as
+    /// before caching was introduced.
+    fn load_remote(url: &str, cache_path: Option<&Path>) -> Result<Self> {
+        let cached = cache_path.and_then(|path| CachedRules::load(path, url));
+
+        let mut req = ureq::get(url);
+        req.timeout_connect(30_000).timeout_read(300_000);
+        if let Some(cached) = &cached {
+            req.set("If-Modified-Since", &cached.last_modified);
+            if let Some(etag) = &cached.etag {
+                req.set("If-None-Match", etag);
+            }
+        }
+        let res = req.call();
+
+        if res.status() == 304 {
+            let cached =
+                cached.context("Server sent 304 Not Modified but no rules cache exists")?;
+            return Self::parse(cached.body.as_bytes());
+        }
+        if res.ok() {
+            let etag = res.header("ETag").map(str::to_owned);
+            let last_modified = res
+                .header("Last-Modified")
+                .map(str::to_owned)
+                .unwrap_or_else(Chrono::get_utc_timestamp_as_rfc2822);
+            let body = res
+                .into_string()
+                .context("Failed to read remote rules body")?;
+            if let Some(path) = cache_path {
+                CachedRules {
+                    url: url.to_owned(),
+                    etag,
+                    last_modified,
+                    body: body.clone(),
+                }
+                .save(path)?;
+            }
+            return Self::parse(body.as_bytes());
+        }
+        if let Some(cached) = cached {
+            eprintln!(
+                "{}: failed to refresh remote rules from {} ({}), using cached copy",
+                crate_name!(),
+                url,
                 res.status_line()
             );
-            Self::parse(res.into_reader())
-        } else {
-            Self::parse(
-                File::open(&source)
-                    .with_context(|| format!("Cannot open rules file {:?}", source))?,
-            )
+            return Self::parse(cached.body.as_bytes());
         }
+        bail!(
+            "Failed to retrieve remote rules from {}: {}",
+            url,
+            res.status_line()
+        )
     }
 }
 
@@ -109,8 +292,11 @@ mod test {
     use super::*;
 
     fn load_rules() -> Rules {
-        Rules::load(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/rules.yaml"))
-            .expect("load fixtures/rules.yaml")
+        Rules::load(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/rules.yaml"),
+            None,
+        )
+        .expect("load fixtures/rules.yaml")
     }
 
     #[test]
@@ -129,23 +315,81 @@ mod test {
     #[test]
     fn load_from_file() {
         let r = load_rules();
-        assert_eq!(r.crit.matches.len(), 2);
-        assert_eq!(r.crit.except.len(), 2);
-        assert_eq!(r.warn.matches.len(), 2);
-        assert_eq!(r.warn.except.len(), 3);
+        assert_eq!(r.crit.matches.len(), 1);
+        assert_eq!(r.crit.matches[0].regexes.len(), 2);
+        assert_eq!(r.crit.except.len(), 1);
+        assert_eq!(r.crit.except[0].regexes.len(), 2);
+        assert_eq!(r.warn.matches.len(), 1);
+        assert_eq!(r.warn.matches[0].regexes.len(), 2);
+        assert_eq!(r.warn.except.len(), 1);
+        assert_eq!(r.warn.except[0].regexes.len(), 3);
+        assert_eq!(r.unknown.matches.len(), 0);
+        assert_eq!(r.unknown.except.len(), 0);
     }
 
     #[test]
     fn load_from_nonexistent_url_should_fail() {
-        assert!(Rules::load("http://no.such.host.example.com/rules").is_err());
+        assert!(Rules::load("http://no.such.host.example.com/rules", None).is_err());
+    }
+
+    #[test]
+    fn load_from_nonexistent_url_falls_back_to_cache() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        CachedRules {
+            url: "http://no.such.host.example.com/rules".to_owned(),
+            etag: None,
+            last_modified: Chrono::get_utc_timestamp_as_rfc2822(),
+            body: std::fs::read_to_string(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/fixtures/rules.yaml"
+            ))
+            .unwrap(),
+        }
+        .save(tf.path())
+        .unwrap();
+        let r = Rules::load("http://no.such.host.example.com/rules", Some(tf.path()))
+            .expect("should fall back to cache instead of failing");
+        assert_eq!(r.crit.matches[0].regexes.len(), 2);
+    }
+
+    #[test]
+    fn cache_for_a_different_url_is_ignored() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        CachedRules {
+            url: "http://example.com/other-rules".to_owned(),
+            etag: None,
+            last_modified: Chrono::get_utc_timestamp_as_rfc2822(),
+            body: String::new(),
+        }
+        .save(tf.path())
+        .unwrap();
+        assert!(CachedRules::load(tf.path(), "http://no.such.host.example.com/rules").is_none());
     }
 
     #[test]
     fn matches_and_exceptions() {
         let r = load_rules();
-        assert!(r.crit.is_match("0 Errors"));
-        assert!(!r.crit.is_match("0 errors"));
-        assert!(r.warn.is_match("some WARN foo"));
-        assert!(!r.warn.is_match("WARN: node[1234]: Exception in function"))
+        assert!(r.crit.is_match(&record_from_line("0 Errors")));
+        assert!(!r.crit.is_match(&record_from_line("0 errors")));
+        assert!(r.warn.is_match(&record_from_line("some WARN foo")));
+        assert!(!r
+            .warn
+            .is_match(&record_from_line("WARN: node[1234]: Exception in function")))
+    }
+
+    #[test]
+    fn scoped_pattern_matches_named_field() {
+        let rules = RuleSet::new(
+            &["PRIORITY:^[0-3]$".to_owned()],
+            &[],
+            "crit",
+        )
+        .unwrap();
+        let mut record = Record::new();
+        record.insert("PRIORITY".to_owned(), Value::String("2".to_owned()));
+        record.insert("MESSAGE".to_owned(), Value::String("all good".to_owned()));
+        assert!(rules.is_match(&record));
+        record.insert("PRIORITY".to_owned(), Value::String("6".to_owned()));
+        assert!(!rules.is_match(&record));
     }
 }
@@ -13,5 +13,5 @@ pub fn fixture(item: &str) -> PathBuf {
 
 lazy_static! {
     pub static ref RULES: Rules =
-        Rules::load(fixture("rules.yaml")).expect("failed to load test rules");
+        Rules::load(fixture("rules.yaml"), None).expect("failed to load test rules");
 }
@@ -1,31 +1,103 @@
-use lazycell::AtomicLazyCell;
+//! Global execution timeout via SIGALRM, killing a runaway `journalctl` child.
+
+use anyhow::{Context, Result};
 use nix::libc;
-use nix::sys::signal::*;
-use nix::unistd::alarm;
+use nix::sys::signal::{kill, SigAction, SigHandler, Signal};
+use nix::unistd::{self, alarm, Pid};
+use std::os::unix::io::RawFd;
 use std::process;
-use {Result, ResultExt};
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::thread;
+use std::time::Duration;
+use structopt::clap::crate_name;
 
-lazy_static! {
-    static ref T: AtomicLazyCell<u32> = AtomicLazyCell::new();
-}
+/// Timeout in seconds, set by `install()` and read back by the watcher thread.
+static TIMEOUT: AtomicU32 = AtomicU32::new(0);
+/// Process group id of the currently running `journalctl` child, if any.
+///
+/// `0` means "no child running". Tracked out-of-band because the watcher
+/// thread runs with no access to `Check` state.
+static CHILD_PGID: AtomicI32 = AtomicI32::new(0);
+/// Write end of the self-pipe the signal handler notifies through.
+static ALARM_PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+/// How long to wait after SIGTERM before escalating to SIGKILL.
+const KILL_GRACE_PERIOD: Duration = Duration::from_millis(500);
 
+/// Signal handler proper: only does async-signal-safe work (`write(2)` on a
+/// self-pipe), then returns. The actual kill-escalation, grace period and
+/// exit happen on the watcher thread started by `install`, since
+/// `thread::sleep` and buffered `println!` are not safe to call here.
 extern "C" fn hdl(_: libc::c_int) {
-    println!(
-        "{} UNKNOWN - timed out after {}s",
-        crate_name!(),
-        T.get().unwrap_or_default()
-    );
-    process::exit(3);
+    let fd = ALARM_PIPE_WRITE.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let _ = unistd::write(fd, &[0u8]);
+    }
 }
 
+/// Installs a SIGALRM handler that terminates a still-running `journalctl`
+/// child (and its whole process group), prints an UNKNOWN result and exits
+/// with code 3 once `timeout` seconds have elapsed.
 pub fn install(timeout: u32) -> Result<()> {
-    T.fill(timeout).expect("BUG: trying to set up alarm twice");
+    TIMEOUT.store(timeout, Ordering::SeqCst);
+
+    let (read_fd, write_fd) = unistd::pipe().context("failed to create alarm self-pipe")?;
+    ALARM_PIPE_WRITE.store(write_fd, Ordering::SeqCst);
+    spawn_watcher(read_fd);
+
     unsafe {
-        sigaction(
-            Signal::SIGALRM,
-            &SigAction::new(SigHandler::Handler(hdl), SaFlags::empty(), SigSet::empty()),
-        )
-    }.chain_err(|| "failed to set signal handler")?;
-    alarm::set(timeout as libc::c_uint);
+        kill_on_alarm()?;
+    }
+    alarm::set(timeout);
+    Ok(())
+}
+
+/// Blocks on the self-pipe and, once the alarm handler signals it, escalates
+/// the child's process group from SIGTERM to SIGKILL with a grace period in
+/// between, then reports the timeout and exits. Runs entirely off the signal
+/// handler so it may freely sleep, print and allocate.
+fn spawn_watcher(read_fd: RawFd) {
+    thread::spawn(move || {
+        let mut buf = [0u8; 1];
+        if unistd::read(read_fd, &mut buf).unwrap_or(0) == 0 {
+            return;
+        }
+
+        let pgid = CHILD_PGID.swap(0, Ordering::SeqCst);
+        if pgid > 0 {
+            let group = Pid::from_raw(-pgid);
+            kill(group, Signal::SIGTERM).ok();
+            thread::sleep(KILL_GRACE_PERIOD);
+            kill(group, Signal::SIGKILL).ok();
+        }
+        println!(
+            "{} UNKNOWN - timed out after {}s",
+            crate_name!(),
+            TIMEOUT.load(Ordering::SeqCst)
+        );
+        process::exit(3);
+    });
+}
+
+unsafe fn kill_on_alarm() -> Result<()> {
+    nix::sys::signal::sigaction(
+        Signal::SIGALRM,
+        &SigAction::new(
+            SigHandler::Handler(hdl),
+            nix::sys::signal::SaFlags::empty(),
+            nix::sys::signal::SigSet::empty(),
+        ),
+    )
+    .context("failed to set signal handler")?;
     Ok(())
 }
+
+/// Registers the process group of a just-spawned `journalctl` child so the
+/// alarm handler can reach it. Must be paired with `clear_child_pgid`.
+pub fn set_child_pgid(pgid: i32) {
+    CHILD_PGID.store(pgid, Ordering::SeqCst);
+}
+
+/// Unregisters the child process group once it has exited normally.
+pub fn clear_child_pgid() {
+    CHILD_PGID.store(0, Ordering::SeqCst);
+}